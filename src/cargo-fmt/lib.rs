@@ -0,0 +1,440 @@
+// Inspired by Paul Woolcock's cargo-fmt (https://github.com/pwoolcoc/cargo-fmt/).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde_json as json;
+
+const SUCCESS: i32 = 0;
+const FAILURE: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    Verbose,
+    Normal,
+    Quiet,
+}
+
+/// Which packages in the workspace should be formatted.
+#[derive(Debug, Clone)]
+pub enum CargoFmtStrategy {
+    /// Format every local (path-based) package, including local path
+    /// dependencies; registry and git dependencies are not formatted.
+    All,
+    /// Format packages that are specified by the command line argument.
+    Some(Vec<String>),
+    /// Format the root package only.
+    Root,
+}
+
+/// A source file belonging to some package, along with the edition that
+/// package was compiled with.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct Target {
+    /// Entry point for a target.
+    path: PathBuf,
+    /// Edition that the target's package is configured to use.
+    edition: String,
+    /// Name of the package the target belongs to.
+    package: String,
+}
+
+/// Output captured from one `rustfmt` invocation, to be flushed atomically
+/// once the invocation completes.
+struct GroupOutput {
+    status: i32,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Format all source files for the given strategy using `rustfmt`, grouping
+/// invocations by the edition each target's package declares so that mixed
+/// editions in a workspace are handled correctly. Groups are run
+/// concurrently, bounded by `jobs`.
+pub fn format_crate(
+    rustfmt: &Path,
+    verbosity: Verbosity,
+    strategy: &CargoFmtStrategy,
+    excludes: &[String],
+    jobs: usize,
+    rustfmt_args: Vec<String>,
+    manifest_path: Option<&Path>,
+) -> Result<i32, io::Error> {
+    let targets = get_targets(strategy, excludes, manifest_path)?;
+
+    let mut by_edition: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+    for target in targets {
+        by_edition.entry(target.edition).or_default().insert(target.path);
+    }
+    let groups: Vec<(String, BTreeSet<PathBuf>)> = by_edition.into_iter().collect();
+    let json_mode = is_json_mode(&rustfmt_args);
+
+    let mut exit_code = SUCCESS;
+    let mut json_records: Vec<json::Value> = Vec::new();
+    for batch in groups.chunks(jobs.max(1)) {
+        let outputs: Vec<Result<GroupOutput, io::Error>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|(edition, paths)| {
+                    scope.spawn(|| {
+                        run_rustfmt(rustfmt, paths, edition, &rustfmt_args, verbosity, json_mode)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("rustfmt worker thread panicked"))
+                .collect()
+        });
+
+        for output in outputs {
+            let output = output?;
+            if json_mode {
+                if !output.stdout.is_empty() {
+                    let records: Vec<json::Value> = json::from_slice(&output.stdout)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                    json_records.extend(records);
+                }
+            } else {
+                io::stdout().write_all(&output.stdout)?;
+            }
+            io::stderr().write_all(&output.stderr)?;
+            exit_code = worst_exit_code(exit_code, output.status);
+        }
+    }
+
+    if json_mode {
+        let merged = json::Value::Array(json_records);
+        io::stdout().write_all(merged.to_string().as_bytes())?;
+    }
+    Ok(exit_code)
+}
+
+/// Whether `rustfmt_args` requests `--emit json`, in which case each group's
+/// JSON output must be merged into a single array rather than streamed.
+/// Recognizes both the split (`--emit json`) and single-token (`--emit=json`)
+/// spellings rustfmt accepts.
+fn is_json_mode(rustfmt_args: &[String]) -> bool {
+    let mut args = rustfmt_args.iter();
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg.strip_prefix("--emit=") {
+            if value == "json" {
+                return true;
+            }
+        } else if arg == "--emit" && args.next().map_or(false, |v| v == "json") {
+            return true;
+        }
+    }
+    false
+}
+
+/// Fold a new group's exit status into the aggregate, keeping the worst
+/// (highest) of the two so a failure in any group fails the whole run.
+fn worst_exit_code(current: i32, status: i32) -> i32 {
+    current.max(status)
+}
+
+/// Run a single `rustfmt` invocation, buffering its stdout and stderr so
+/// that the caller can flush them atomically relative to other
+/// concurrently running groups instead of letting their output interleave.
+fn run_rustfmt(
+    rustfmt: &Path,
+    paths: &BTreeSet<PathBuf>,
+    edition: &str,
+    rustfmt_args: &[String],
+    verbosity: Verbosity,
+    json_mode: bool,
+) -> Result<GroupOutput, io::Error> {
+    if paths.is_empty() {
+        return Ok(GroupOutput {
+            status: SUCCESS,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        });
+    }
+
+    let mut header = Vec::new();
+    if verbosity == Verbosity::Verbose && !json_mode {
+        write!(header, "rustfmt").unwrap();
+        for path in paths {
+            write!(header, " {}", path.display()).unwrap();
+        }
+        writeln!(header, " --edition {}", edition).unwrap();
+    }
+
+    let stdout = if verbosity == Verbosity::Quiet && !json_mode {
+        Stdio::null()
+    } else {
+        Stdio::piped()
+    };
+
+    let command = Command::new(rustfmt)
+        .stdout(stdout)
+        .stderr(Stdio::piped())
+        .args(paths)
+        .args(["--edition", edition])
+        .args(rustfmt_args)
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::Other,
+                "Could not run rustfmt, please make sure it is in your PATH.",
+            ),
+            _ => e,
+        })?;
+
+    let output = command.wait_with_output()?;
+    let mut stdout_buf = header;
+    stdout_buf.extend_from_slice(&output.stdout);
+
+    let status = if output.status.success() {
+        SUCCESS
+    } else {
+        output.status.code().unwrap_or(FAILURE)
+    };
+    Ok(GroupOutput {
+        status,
+        stdout: stdout_buf,
+        stderr: output.stderr,
+    })
+}
+
+/// Run `cargo metadata` and return the parsed document.
+fn get_cargo_metadata(manifest_path: Option<&Path>) -> Result<json::Value, io::Error> {
+    let mut command = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()));
+    command.arg("metadata").arg("--format-version=1");
+    if let Some(manifest_path) = manifest_path {
+        command.arg("--manifest-path").arg(manifest_path);
+    }
+
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "`cargo metadata` failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+    json::from_slice(&output.stdout)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+/// Collect the targets that should be formatted for `strategy`, each tagged
+/// with the edition and package name of the package it belongs to. Packages
+/// named in `excludes` are dropped regardless of `strategy`.
+fn get_targets(
+    strategy: &CargoFmtStrategy,
+    excludes: &[String],
+    manifest_path: Option<&Path>,
+) -> Result<BTreeSet<Target>, io::Error> {
+    let metadata = get_cargo_metadata(manifest_path)?;
+    let root_id = metadata["resolve"]["root"].as_str();
+    if matches!(strategy, CargoFmtStrategy::Root) && root_id.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "could not determine root package for this workspace; use --all or -p",
+        ));
+    }
+    let packages = metadata["packages"].as_array().cloned().unwrap_or_default();
+    let known_names: BTreeSet<&str> = packages
+        .iter()
+        .filter_map(|package| package["name"].as_str())
+        .collect();
+    if let CargoFmtStrategy::Some(names) = strategy {
+        check_known_packages(names, &known_names)?;
+    }
+    check_known_packages(excludes, &known_names)?;
+
+    let mut targets = BTreeSet::new();
+    for package in &packages {
+        let id = package["id"].as_str().unwrap_or_default();
+        let package_name = package["name"].as_str().unwrap_or_default();
+        let is_local = package["source"].is_null();
+        if !should_format_package(strategy, excludes, root_id, is_local, id, package_name) {
+            continue;
+        }
+
+        let edition = package["edition"]
+            .as_str()
+            .unwrap_or("2015")
+            .to_owned();
+        for target in package["targets"].as_array().into_iter().flatten() {
+            if let Some(path) = target["src_path"].as_str() {
+                targets.insert(Target {
+                    path: PathBuf::from(path),
+                    edition: edition.clone(),
+                    package: package_name.to_owned(),
+                });
+            }
+        }
+    }
+    Ok(targets)
+}
+
+/// Check that every name in `names` matches a package in the workspace,
+/// erroring out (mirroring the `-p`/`--exclude` convention of other cargo
+/// subcommands) instead of silently no-opping on a typo'd package name.
+fn check_known_packages(names: &[String], known_names: &BTreeSet<&str>) -> Result<(), io::Error> {
+    let unknown: Vec<&str> = names
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !known_names.contains(name))
+        .collect();
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "package(s) not found in this workspace: {}",
+                unknown.join(", ")
+            ),
+        ))
+    }
+}
+
+/// Whether a package should be formatted under `strategy`, given its id,
+/// name, whether it's a local (path-based) package, and the workspace root
+/// package's id (if known). Packages named in `excludes` are always
+/// dropped, regardless of `strategy`.
+fn should_format_package(
+    strategy: &CargoFmtStrategy,
+    excludes: &[String],
+    root_id: Option<&str>,
+    is_local: bool,
+    id: &str,
+    package_name: &str,
+) -> bool {
+    if excludes.iter().any(|e| e == package_name) {
+        return false;
+    }
+    match strategy {
+        // Without `--no-deps`, `packages` also contains every registry/git
+        // dependency; restrict `--all` to local (path-based) packages, as
+        // documented.
+        CargoFmtStrategy::All => is_local,
+        CargoFmtStrategy::Root => root_id == Some(id),
+        CargoFmtStrategy::Some(names) => names.iter().any(|n| n == package_name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_strategy_matches_only_root_package() {
+        assert!(should_format_package(
+            &CargoFmtStrategy::Root,
+            &[],
+            Some("root 0.1.0 (path+file:///ws)"),
+            true,
+            "root 0.1.0 (path+file:///ws)",
+            "root",
+        ));
+        assert!(!should_format_package(
+            &CargoFmtStrategy::Root,
+            &[],
+            Some("root 0.1.0 (path+file:///ws)"),
+            true,
+            "member 0.1.0 (path+file:///ws/member)",
+            "member",
+        ));
+    }
+
+    #[test]
+    fn root_strategy_matches_nothing_when_root_is_unknown() {
+        assert!(!should_format_package(
+            &CargoFmtStrategy::Root,
+            &[],
+            None,
+            true,
+            "root 0.1.0 (path+file:///ws)",
+            "root",
+        ));
+    }
+
+    #[test]
+    fn all_strategy_excludes_registry_dependencies() {
+        assert!(should_format_package(
+            &CargoFmtStrategy::All,
+            &[],
+            Some("root 0.1.0"),
+            true,
+            "dep 0.1.0 (path+file:///ws/dep)",
+            "dep",
+        ));
+        assert!(!should_format_package(
+            &CargoFmtStrategy::All,
+            &[],
+            Some("root 0.1.0"),
+            false,
+            "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+            "serde",
+        ));
+    }
+
+    #[test]
+    fn exclude_overrides_all_strategy() {
+        assert!(!should_format_package(
+            &CargoFmtStrategy::All,
+            &[String::from("dep")],
+            Some("root 0.1.0"),
+            true,
+            "dep 0.1.0 (path+file:///ws/dep)",
+            "dep",
+        ));
+    }
+
+    #[test]
+    fn some_strategy_matches_named_packages_only() {
+        let names = vec![String::from("a"), String::from("b")];
+        assert!(should_format_package(
+            &CargoFmtStrategy::Some(names.clone()),
+            &[],
+            None,
+            true,
+            "a 0.1.0",
+            "a",
+        ));
+        assert!(!should_format_package(
+            &CargoFmtStrategy::Some(names),
+            &[],
+            None,
+            true,
+            "c 0.1.0",
+            "c",
+        ));
+    }
+
+    #[test]
+    fn check_known_packages_errors_on_typo() {
+        let known: BTreeSet<&str> = ["a", "b"].into_iter().collect();
+        assert!(check_known_packages(&[String::from("a")], &known).is_ok());
+        assert!(check_known_packages(&[String::from("typo")], &known).is_err());
+    }
+
+    #[test]
+    fn detects_json_emit_mode() {
+        assert!(is_json_mode(&[
+            String::from("--emit"),
+            String::from("json"),
+        ]));
+        assert!(is_json_mode(&[String::from("--emit=json")]));
+        assert!(!is_json_mode(&[String::from("--check")]));
+        assert!(!is_json_mode(&[String::from("--emit")]));
+        assert!(!is_json_mode(&[String::from("--emit=files")]));
+    }
+
+    #[test]
+    fn worst_exit_code_keeps_the_highest_status() {
+        assert_eq!(worst_exit_code(SUCCESS, SUCCESS), SUCCESS);
+        assert_eq!(worst_exit_code(SUCCESS, FAILURE), FAILURE);
+        assert_eq!(worst_exit_code(FAILURE, SUCCESS), FAILURE);
+    }
+}
@@ -42,6 +42,10 @@ pub struct Opts {
     #[structopt(short = "p", long = "package", value_name = "package")]
     packages: Vec<String>,
 
+    /// Specify package to skip when formatting with `--all`
+    #[structopt(long = "exclude", value_name = "package")]
+    excludes: Vec<String>,
+
     /// Specify path to Cargo.toml
     #[structopt(long = "manifest-path", value_name = "manifest-path")]
     manifest_path: Option<String>,
@@ -62,6 +66,10 @@ pub struct Opts {
     /// Run rustfmt in check mode
     #[structopt(long = "check")]
     check: bool,
+
+    /// Number of rustfmt instances to run in parallel
+    #[structopt(long = "jobs", short = "j", value_name = "jobs")]
+    jobs: Option<usize>,
 }
 
 fn main() {
@@ -108,6 +116,11 @@ fn execute() -> i32 {
         return handle_command_status(get_rustfmt_info(&opts.rustfmt_options));
     }
 
+    if !opts.excludes.is_empty() && !opts.format_all {
+        print_usage_to_stderr("`--exclude` can only be used together with `--all`");
+        return FAILURE;
+    }
+
     let strategy = CargoFmtStrategy::from_opts(&opts);
     let mut rustfmt_args = opts.rustfmt_options;
     if opts.check {
@@ -134,10 +147,15 @@ fn execute() -> i32 {
         }
         None => None,
     };
+    let jobs = opts
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
     let status = cargo_fmt::format_crate(
         &rustfmt_path(),
         verbosity,
         &strategy,
+        &opts.excludes,
+        jobs,
         rustfmt_args,
         manifest_path.as_deref(),
     );